@@ -1,12 +1,24 @@
 use lsp_server::{Connection, ExtractError, Message, RequestId, Response};
 use lsp_types::{
-    request::{DocumentHighlightRequest, HoverRequest, InlayHintRequest, Request},
-    DocumentHighlight, DocumentHighlightKind, HoverContents, HoverProviderCapability,
-    InitializeParams, InlayHint, InlayHintKind, InlayHintLabel, MarkedString, OneOf, Position,
-    Range, SemanticTokenType, SemanticTokensLegend, SemanticTokensOptions,
-    SemanticTokensServerCapabilities, ServerCapabilities, TextDocumentPositionParams,
-    TextDocumentSyncCapability, TextDocumentSyncKind,
+    notification::{
+        DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+        Notification as NotificationTrait, PublishDiagnostics,
+    },
+    request::{
+        Completion, DocumentHighlightRequest, GotoDefinition, HoverRequest, InlayHintRequest,
+        References, Request, SemanticTokensFullRequest,
+    },
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionResponse,
+    CompletionTextEdit, Diagnostic, DiagnosticRelatedInformation, DiagnosticSeverity,
+    DocumentHighlight, DocumentHighlightKind, GotoDefinitionResponse, HoverContents,
+    HoverProviderCapability, InitializeParams, InlayHint, InlayHintKind, InlayHintLabel,
+    Location, MarkedString, OneOf, Position, PositionEncodingKind, PublishDiagnosticsParams,
+    Range, SemanticToken, SemanticTokenType, SemanticTokens, SemanticTokensLegend,
+    SemanticTokensOptions, SemanticTokensResult, SemanticTokensServerCapabilities,
+    ServerCapabilities, TextDocumentPositionParams, TextDocumentSyncCapability,
+    TextDocumentSyncKind, TextEdit, Url,
 };
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use tree_sitter::{Node, Point, Query};
 use whitespace::{
@@ -23,18 +35,33 @@ fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
     // also be implemented to use sockets or HTTP.
     let (connection, io_threads) = Connection::stdio();
 
+    // The position encoding depends on what the client advertises, so the initialize
+    // handshake has to be split: inspect the client's capabilities before replying with ours.
+    let (initialize_id, initialize_params) = connection.initialize_start()?;
+    let initialize_params: InitializeParams = serde_json::from_value(initialize_params).unwrap();
+    let position_encoding = negotiate_position_encoding(&initialize_params);
+
     // Run the server and wait for the two threads to end (typically by trigger LSP Exit event).
-    let server_capabilities = serde_json::to_value(ServerCapabilities {
-        // definition_provider: Some(OneOf::Left(true)),
+    let server_capabilities = ServerCapabilities {
+        position_encoding: Some(position_encoding.clone()),
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
         // inline_value_provider
         inlay_hint_provider: Some(OneOf::Left(true)),
         text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
         hover_provider: Some(HoverProviderCapability::Simple(true)),
         document_highlight_provider: Some(OneOf::Left(true)),
+        completion_provider: Some(CompletionOptions::default()),
         semantic_tokens_provider: Some(SemanticTokensServerCapabilities::SemanticTokensOptions(
             SemanticTokensOptions {
                 legend: SemanticTokensLegend {
-                    token_types: vec![SemanticTokenType::KEYWORD],
+                    token_types: vec![
+                        SemanticTokenType::KEYWORD,
+                        SemanticTokenType::FUNCTION,
+                        SemanticTokenType::OPERATOR,
+                        SemanticTokenType::NUMBER,
+                        SemanticTokenType::TYPE,
+                    ],
                     token_modifiers: vec![],
                 },
                 range: None,
@@ -43,10 +70,11 @@ fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
             },
         )),
         ..Default::default()
-    })
-    .unwrap();
-    let initialization_params = connection.initialize(server_capabilities)?;
-    main_loop(connection, initialization_params)?;
+    };
+    let initialize_data = serde_json::json!({ "capabilities": server_capabilities });
+    connection.initialize_finish(initialize_id, initialize_data)?;
+
+    main_loop(connection, position_encoding)?;
     io_threads.join()?;
 
     // Shut down gracefully.
@@ -54,12 +82,29 @@ fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
     Ok(())
 }
 
+/// Prefers UTF-8 when the client supports it, since Whitespace source is mostly
+/// single-byte control characters and UTF-8 needs no conversion on our side.
+/// Falls back to UTF-16, the LSP default for clients that don't advertise the capability.
+fn negotiate_position_encoding(params: &InitializeParams) -> PositionEncodingKind {
+    let supported = params
+        .capabilities
+        .general
+        .as_ref()
+        .and_then(|general| general.position_encodings.as_ref());
+    match supported {
+        Some(encodings) if encodings.contains(&PositionEncodingKind::UTF8) => {
+            PositionEncodingKind::UTF8
+        }
+        _ => PositionEncodingKind::UTF16,
+    }
+}
+
 fn main_loop(
     connection: Connection,
-    params: serde_json::Value,
+    position_encoding: PositionEncodingKind,
 ) -> Result<(), Box<dyn Error + Sync + Send>> {
-    let _params: InitializeParams = serde_json::from_value(params).unwrap();
     eprintln!("starting main loop");
+    let mut documents: HashMap<Url, String> = HashMap::new();
     for msg in &connection.receiver {
         eprintln!("got msg: {msg:?}");
         match msg {
@@ -72,7 +117,7 @@ fn main_loop(
                         let (id, params) = cast::<HoverRequest>(req)?;
                         eprintln!("got Hover request #{id}: {params:?}");
                         let doc_params = &params.text_document_position_params;
-                        let source = read_file(doc_params);
+                        let source = read_file(&documents, doc_params);
                         let tree = tokenize(&source);
                         let source_file = tree.root_node();
                         let pos = doc_params.position;
@@ -111,12 +156,10 @@ fn main_loop(
                             _ => node.kind().to_string(),
                         };
                         // let contents = "".to_string();
+                        let line_index = LineIndex::new(&source, position_encoding.clone());
                         let response_params = lsp_types::Hover {
                             contents: HoverContents::Scalar(MarkedString::String(contents)),
-                            range: Some(Range {
-                                start: node.start_position().to_lsp_pos(),
-                                end: node.end_position().to_lsp_pos(),
-                            }),
+                            range: Some(node.to_lsp_range(&line_index)),
                             // range: None,
                         };
                         let result = Some(serde_json::to_value(response_params).unwrap());
@@ -132,12 +175,14 @@ fn main_loop(
                     DocumentHighlightRequest::METHOD => {
                         let (id, params) = cast::<DocumentHighlightRequest>(req)?;
                         eprintln!("got DocumentHighlight request #{id}: {params:?}");
-                        let tree = lex_file(&params.text_document_position_params);
+                        let source = read_file(&documents, &params.text_document_position_params);
+                        let tree = tokenize(&source);
                         let source_file = tree.root_node();
+                        let line_index = LineIndex::new(&source, position_encoding.clone());
                         let mut cursor = source_file.walk();
                         let highlights = source_file
                             .children(&mut cursor)
-                            .map(|node| node.to_document_highlight())
+                            .map(|node| node.to_document_highlight(&line_index))
                             .collect::<Vec<_>>();
                         let result = Some(serde_json::to_value(highlights).unwrap());
                         let resp = Response {
@@ -152,10 +197,10 @@ fn main_loop(
                         let (id, params) = cast::<InlayHintRequest>(req)?;
                         eprintln!("got InlayHint request #{id}: {params:?}");
 
-                        let path = params.text_document.uri.to_file_path().unwrap();
-                        let source = std::fs::read_to_string(path).unwrap();
+                        let source = resolve_source(&documents, &params.text_document.uri);
                         let ast = whitespace::parse::tree_sitter::parse(&source).unwrap();
                         let flows = ast.flow_control_ops(&source);
+                        let line_index = LineIndex::new(&source, position_encoding.clone());
                         let hints = flows
                             .iter()
                             .map(|(n, op)| {
@@ -170,7 +215,7 @@ fn main_loop(
                                         format!("{}", op).replace("label ", ""),
                                     ),
                                     kind: Some(InlayHintKind::TYPE),
-                                    position: n.end_position().to_lsp_pos(),
+                                    position: line_index.to_lsp_pos(n.end_position()),
                                     text_edits: None,
                                     tooltip: None,
                                     padding_left: None,
@@ -203,49 +248,238 @@ fn main_loop(
                         connection.sender.send(Message::Response(resp))?;
                         continue;
                     }
+                    SemanticTokensFullRequest::METHOD => {
+                        let (id, params) = cast::<SemanticTokensFullRequest>(req)?;
+                        eprintln!("got SemanticTokensFull request #{id}: {params:?}");
+                        let source = resolve_source(&documents, &params.text_document.uri);
+                        let tree = tokenize(&source);
+                        let root = tree.root_node();
+                        let line_index = LineIndex::new(&source, position_encoding.clone());
+
+                        let mut tokens = NodeIterator::new(root)
+                            .filter(|node| !has_operand_child(node))
+                            .filter_map(|node| semantic_token_type(&node).map(|ty| (node, ty)))
+                            .collect::<Vec<_>>();
+                        tokens.sort_by_key(|(node, _)| {
+                            let pos = node.start_position();
+                            (pos.row, pos.column)
+                        });
+
+                        let mut data = Vec::with_capacity(tokens.len());
+                        let mut prev_line = 0u32;
+                        let mut prev_start = 0u32;
+                        for (node, token_type) in tokens {
+                            let start = line_index.to_lsp_pos(node.start_position());
+                            let end = line_index.to_lsp_pos(node.end_position());
+                            if start.line != end.line {
+                                // Semantic tokens can't span lines; a node that does
+                                // (e.g. one whose range swallows a trailing LF) can't
+                                // be expressed as a single length, so skip it rather
+                                // than underflow `end.character - start.character`.
+                                continue;
+                            }
+                            let delta_line = start.line - prev_line;
+                            let delta_start = if delta_line == 0 {
+                                start.character - prev_start
+                            } else {
+                                start.character
+                            };
+                            data.push(SemanticToken {
+                                delta_line,
+                                delta_start,
+                                length: end.character - start.character,
+                                token_type,
+                                token_modifiers_bitset: 0,
+                            });
+                            prev_line = start.line;
+                            prev_start = start.character;
+                        }
+
+                        let result = Some(
+                            serde_json::to_value(SemanticTokensResult::Tokens(SemanticTokens {
+                                result_id: None,
+                                data,
+                            }))
+                            .unwrap(),
+                        );
+                        let resp = Response {
+                            id,
+                            result,
+                            error: None,
+                        };
+                        connection.sender.send(Message::Response(resp))?;
+                        continue;
+                    }
+                    GotoDefinition::METHOD => {
+                        let (id, params) = cast::<GotoDefinition>(req)?;
+                        eprintln!("got GotoDefinition request #{id}: {params:?}");
+                        let doc_params = &params.text_document_position_params;
+                        let uri = doc_params.text_document.uri.clone();
+                        let source = read_file(&documents, doc_params);
+                        let tree = tokenize(&source);
+                        let root = tree.root_node();
+                        let line_index = LineIndex::new(&source, position_encoding.clone());
+                        let result = operand_label_at(&root, doc_params.position).and_then(|label| {
+                            label_definitions(&root)
+                                .get(&label)
+                                .and_then(|nodes| nodes.first())
+                                .map(|node| {
+                                    GotoDefinitionResponse::Scalar(node.to_location(&uri, &line_index))
+                                })
+                        });
+                        let result = Some(serde_json::to_value(result).unwrap());
+                        let resp = Response {
+                            id,
+                            result,
+                            error: None,
+                        };
+                        connection.sender.send(Message::Response(resp))?;
+                        continue;
+                    }
+                    References::METHOD => {
+                        let (id, params) = cast::<References>(req)?;
+                        eprintln!("got References request #{id}: {params:?}");
+                        let doc_params = &params.text_document_position;
+                        let uri = doc_params.text_document.uri.clone();
+                        let source = read_file(&documents, doc_params);
+                        let tree = tokenize(&source);
+                        let root = tree.root_node();
+                        let line_index = LineIndex::new(&source, position_encoding.clone());
+                        let ast = whitespace::parse::tree_sitter::parse(&source).unwrap();
+                        let flows = ast.flow_control_ops(&source);
+                        let result = operand_label_at(&root, doc_params.position).map(|label| {
+                            flows
+                                .iter()
+                                .filter_map(|(n, op)| match op {
+                                    FlowControlOp::Label(l) if *l == label => {
+                                        Some(n.to_location(&uri, &line_index))
+                                    }
+                                    _ => None,
+                                })
+                                .collect::<Vec<_>>()
+                        });
+                        let result = Some(serde_json::to_value(result).unwrap());
+                        let resp = Response {
+                            id,
+                            result,
+                            error: None,
+                        };
+                        connection.sender.send(Message::Response(resp))?;
+                        continue;
+                    }
+                    Completion::METHOD => {
+                        let (id, params) = cast::<Completion>(req)?;
+                        eprintln!("got Completion request #{id}: {params:?}");
+                        let doc_params = &params.text_document_position;
+                        let source = read_file(&documents, doc_params);
+                        let tree = tokenize(&source);
+                        let root = tree.root_node();
+                        let line_index = LineIndex::new(&source, position_encoding.clone());
+
+                        let items = match flow_control_operand_at(&root, doc_params.position) {
+                            Some(current) => {
+                                let replace_range = current
+                                    .map(|node| node.to_lsp_range(&line_index))
+                                    .unwrap_or(Range {
+                                        start: doc_params.position,
+                                        end: doc_params.position,
+                                    });
+                                label_definitions(&root)
+                                    .values()
+                                    .filter_map(|nodes| nodes.first())
+                                    .map(|node| {
+                                        let raw =
+                                            node.utf8_text(source.as_bytes()).unwrap_or_default();
+                                        CompletionItem {
+                                            label: to_visible(raw),
+                                            kind: Some(CompletionItemKind::VARIABLE),
+                                            text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                                                range: replace_range,
+                                                new_text: raw.to_string(),
+                                            })),
+                                            ..Default::default()
+                                        }
+                                    })
+                                    .collect::<Vec<_>>()
+                            }
+                            None => Vec::new(),
+                        };
+
+                        let result =
+                            Some(serde_json::to_value(CompletionResponse::Array(items)).unwrap());
+                        let resp = Response {
+                            id,
+                            result,
+                            error: None,
+                        };
+                        connection.sender.send(Message::Response(resp))?;
+                        continue;
+                    }
                     _ => {
                         eprintln!("got unknown request: {req:?}");
                     }
                 }
-                // match cast::<GotoDefinition>(req) {
-                //     Ok((id, params)) => {
-                //         eprintln!("got gotoDefinition request #{id}: {params:?}");
-                //         let result = Some(GotoDefinitionResponse::Array(Vec::new()));
-                //         let result = serde_json::to_value(&result).unwrap();
-                //         let resp = Response {
-                //             id,
-                //             result: Some(result),
-                //             error: None,
-                //         };
-                //         connection.sender.send(Message::Response(resp))?;
-                //         continue;
-                //     }
-                //     Err(err @ ExtractError::JsonError { .. }) => panic!("{err:?}"),
-                //     Err(ExtractError::MethodMismatch(req)) => req,
-                // };
-                // ...
             }
             Message::Response(resp) => {
                 eprintln!("got response: {resp:?}");
             }
             Message::Notification(not) => {
                 eprintln!("got notification: {not:?}");
+                match not.method.as_str() {
+                    DidOpenTextDocument::METHOD => {
+                        let params = cast_notification::<DidOpenTextDocument>(not)?;
+                        let uri = params.text_document.uri;
+                        documents.insert(uri.clone(), params.text_document.text.clone());
+                        publish_diagnostics(
+                            &connection,
+                            uri,
+                            &params.text_document.text,
+                            position_encoding.clone(),
+                        )?;
+                    }
+                    DidChangeTextDocument::METHOD => {
+                        let params = cast_notification::<DidChangeTextDocument>(not)?;
+                        let uri = params.text_document.uri;
+                        // Full sync: the sole content change carries the whole document.
+                        if let Some(change) = params.content_changes.into_iter().last() {
+                            documents.insert(uri.clone(), change.text.clone());
+                            publish_diagnostics(
+                                &connection,
+                                uri,
+                                &change.text,
+                                position_encoding.clone(),
+                            )?;
+                        }
+                    }
+                    DidCloseTextDocument::METHOD => {
+                        let params = cast_notification::<DidCloseTextDocument>(not)?;
+                        let uri = params.text_document.uri;
+                        documents.remove(&uri);
+                        // The client owns the buffer no longer; clear whatever
+                        // diagnostics we last published for it so they don't
+                        // linger after the document is gone.
+                        clear_diagnostics(&connection, uri)?;
+                    }
+                    _ => {}
+                }
             }
         }
     }
     Ok(())
 }
 
-fn lex_file(params: &TextDocumentPositionParams) -> tree_sitter::Tree {
-    let file = read_file(params);
-    let src = file.as_str();
-    tokenize(src)
+fn read_file(documents: &HashMap<Url, String>, params: &TextDocumentPositionParams) -> String {
+    resolve_source(documents, &params.text_document.uri)
 }
 
-fn read_file(params: &TextDocumentPositionParams) -> String {
-    let path = params.text_document.uri.to_file_path().unwrap();
-    let file = std::fs::read_to_string(path).unwrap();
-    file
+/// Looks up an open document's in-memory contents, falling back to disk for
+/// documents the client never opened (or already closed).
+fn resolve_source(documents: &HashMap<Url, String>, uri: &Url) -> String {
+    if let Some(source) = documents.get(uri) {
+        return source.clone();
+    }
+    let path = uri.to_file_path().unwrap();
+    std::fs::read_to_string(path).unwrap()
 }
 
 fn cast<R>(
@@ -258,40 +492,311 @@ where
     req.extract(R::METHOD)
 }
 
-trait RangeExt {
-    fn to_ts_point(&self) -> tree_sitter::Point;
-    fn to_lsp_pos(&self) -> lsp_types::Position;
+/// Indices into the `SemanticTokensLegend` declared in `main`; keep in sync with
+/// the order of `token_types` there.
+#[repr(u32)]
+enum SemanticTokenKind {
+    Keyword = 0,
+    Function = 1,
+    Operator = 2,
+    Number = 3,
+    Type = 4,
+}
+
+const ARITHMETIC_OPS: &[&str] = &["add", "sub", "mul", "div", "mod"];
+const STACK_OPS: &[&str] = &["push", "dup", "swap", "discard", "copy", "slide"];
+const HEAP_OPS: &[&str] = &["store", "retrieve"];
+const IO_OPS: &[&str] = &["printchar", "printnum", "readchar", "readnum"];
+
+/// True for an `op_*` node whose own byte range swallows an operand child
+/// (`num` for push/copy/slide, `label` for call/jmp/jz/jn).
+///
+/// Such a node must not also get a semantic token: the LSP spec requires
+/// tokens to be non-overlapping, and emitting one for the op node on top of
+/// the one for its operand would overlap every instruction that has one.
+fn has_operand_child(node: &Node) -> bool {
+    if !node.kind().starts_with("op") {
+        return false;
+    }
+    let mut cursor = node.walk();
+    node.children(&mut cursor)
+        .any(|child| matches!(child.kind(), "num" | "label"))
+}
+
+/// Classifies a node for semantic highlighting, or `None` if it carries no
+/// token of its own (e.g. `source_file`, whitespace trivia).
+fn semantic_token_type(node: &Node) -> Option<u32> {
+    let kind = match node.kind() {
+        "num" => SemanticTokenKind::Number,
+        "label" => SemanticTokenKind::Type,
+        kind if kind.starts_with("op") => {
+            let op = kind.trim_start_matches("op").trim_start_matches('_');
+            if ARITHMETIC_OPS.contains(&op) {
+                SemanticTokenKind::Operator
+            } else if STACK_OPS.contains(&op) || HEAP_OPS.contains(&op) || IO_OPS.contains(&op) {
+                SemanticTokenKind::Function
+            } else {
+                // call, jmp, jz, jn, ret, exit, and anything else flow-control.
+                SemanticTokenKind::Keyword
+            }
+        }
+        _ => return None,
+    };
+    Some(kind as u32)
+}
+
+fn cast_notification<N>(
+    not: lsp_server::Notification,
+) -> Result<N::Params, ExtractError<lsp_server::Notification>>
+where
+    N: NotificationTrait,
+    N::Params: serde::de::DeserializeOwned,
+{
+    not.extract(N::METHOD)
 }
 
-impl RangeExt for tree_sitter::Point {
-    fn to_ts_point(&self) -> tree_sitter::Point {
-        *self
+fn publish_diagnostics(
+    connection: &Connection,
+    uri: Url,
+    source: &str,
+    position_encoding: PositionEncodingKind,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let diagnostics = diagnostics_for_source(source, &uri, position_encoding);
+    let params = PublishDiagnosticsParams {
+        uri,
+        diagnostics,
+        version: None,
+    };
+    let notification =
+        lsp_server::Notification::new(PublishDiagnostics::METHOD.to_string(), params);
+    connection.sender.send(Message::Notification(notification))?;
+    Ok(())
+}
+
+/// Publishes an empty diagnostics set for `uri`, clearing whatever was last
+/// published for it (e.g. once the client closes the document).
+fn clear_diagnostics(connection: &Connection, uri: Url) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let params = PublishDiagnosticsParams {
+        uri,
+        diagnostics: Vec::new(),
+        version: None,
+    };
+    let notification =
+        lsp_server::Notification::new(PublishDiagnostics::METHOD.to_string(), params);
+    connection.sender.send(Message::Notification(notification))?;
+    Ok(())
+}
+
+/// Validates labels: every targeted `Label` must be defined exactly once, and
+/// a defined `Label` that nothing ever targets is worth flagging too.
+fn diagnostics_for_source(
+    source: &str,
+    uri: &Url,
+    position_encoding: PositionEncodingKind,
+) -> Vec<Diagnostic> {
+    let tree = tokenize(source);
+    let root = tree.root_node();
+    let defined = label_definitions(&root);
+    let line_index = LineIndex::new(source, position_encoding);
+
+    let mut diagnostics = Vec::new();
+
+    for nodes in defined.values() {
+        let Some((first, rest)) = nodes.split_first() else {
+            continue;
+        };
+        for duplicate in rest {
+            diagnostics.push(Diagnostic {
+                range: duplicate.to_lsp_range(&line_index),
+                severity: Some(DiagnosticSeverity::ERROR),
+                message: "label is already defined".to_string(),
+                related_information: Some(vec![DiagnosticRelatedInformation {
+                    location: Location {
+                        uri: uri.clone(),
+                        range: first.to_lsp_range(&line_index),
+                    },
+                    message: "first defined here".to_string(),
+                }]),
+                ..Default::default()
+            });
+        }
+    }
+
+    let ast = whitespace::parse::tree_sitter::parse(source).unwrap();
+    let flows = ast.flow_control_ops(source);
+    let mut targeted = HashSet::new();
+    for (node, op) in &flows {
+        if let FlowControlOp::Label(label) = op {
+            targeted.insert(label.clone());
+            if !defined.contains_key(label) {
+                let range = operand_label_node(node)
+                    .unwrap_or(*node)
+                    .to_lsp_range(&line_index);
+                diagnostics.push(Diagnostic {
+                    range,
+                    severity: Some(DiagnosticSeverity::ERROR),
+                    message: "label is never defined".to_string(),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+
+    for (label, nodes) in &defined {
+        if !targeted.contains(label) {
+            for node in nodes {
+                diagnostics.push(Diagnostic {
+                    range: node.to_lsp_range(&line_index),
+                    severity: Some(DiagnosticSeverity::WARNING),
+                    message: "label is never targeted".to_string(),
+                    ..Default::default()
+                });
+            }
+        }
     }
 
-    fn to_lsp_pos(&self) -> lsp_types::Position {
+    diagnostics
+}
+
+/// Maps tree-sitter `Point`s (line + byte column) to LSP `Position`s in the
+/// encoding negotiated with the client at `initialize` time.
+///
+/// Whitespace programs treat every byte that isn't space/tab/newline as a
+/// comment, so comment text may be arbitrary multi-byte UTF-8 (emoji, CJK);
+/// a plain byte-to-`character` cast would misplace any range on such a line.
+struct LineIndex<'a> {
+    lines: Vec<&'a str>,
+    encoding: PositionEncodingKind,
+}
+
+impl<'a> LineIndex<'a> {
+    fn new(source: &'a str, encoding: PositionEncodingKind) -> Self {
+        Self {
+            lines: source.lines().collect(),
+            encoding,
+        }
+    }
+
+    fn to_lsp_pos(&self, point: Point) -> Position {
+        if self.encoding == PositionEncodingKind::UTF8 {
+            return Position {
+                line: point.row as u32,
+                character: point.column as u32,
+            };
+        }
+        let line = self.lines.get(point.row).copied().unwrap_or("");
+        let byte_col = point.column.min(line.len());
         Position {
-            line: self.row as u32,
-            character: self.column as u32,
+            line: point.row as u32,
+            character: line[..byte_col].encode_utf16().count() as u32,
+        }
+    }
+}
+
+/// Finds the `label` node under the cursor, if any, and converts it to a `Label`.
+///
+/// Works whether the cursor is on a flow-control operand (`call`/`jmp`/`jz`/`jn`)
+/// or on the label of a `mark` declaration, since both are plain `label` nodes.
+fn operand_label_at(root: &Node, pos: Position) -> Option<Label> {
+    let point = Point::new(pos.line as usize, pos.character as usize);
+    let mut node = root.descendant_for_point_range(point, point)?;
+    while IGNORED_RULES.contains(&node.kind()) {
+        node = node.parent()?;
+    }
+    if node.kind() != "label" {
+        return None;
+    }
+    node.try_into().ok()
+}
+
+const FLOW_CONTROL_OPS: &[&str] = &["call", "jmp", "jz", "jn"];
+
+/// Checks whether the cursor sits inside a `call`/`jmp`/`jz`/`jn` operand.
+///
+/// Returns `None` if completion shouldn't trigger here. Otherwise returns
+/// `Some(node)`, where `node` is the partially-typed `label` to replace, or
+/// `None` if the operand is still empty (the cursor is right after the opcode).
+fn flow_control_operand_at<'a>(root: &Node<'a>, pos: Position) -> Option<Option<Node<'a>>> {
+    let point = Point::new(pos.line as usize, pos.character as usize);
+    let mut node = root.descendant_for_point_range(point, point)?;
+    while IGNORED_RULES.contains(&node.kind()) {
+        node = node.parent()?;
+    }
+    let (op_node, label_node) = if node.kind() == "label" {
+        (node.parent()?, Some(node))
+    } else {
+        (node, None)
+    };
+    let op = op_node.kind().trim_start_matches("op").trim_start_matches('_');
+    if FLOW_CONTROL_OPS.contains(&op) {
+        Some(label_node)
+    } else {
+        None
+    }
+}
+
+/// Finds the operand `label` child of a flow-control op node (`call`/`jmp`/`jz`/`jn`).
+fn operand_label_node<'a>(node: &Node<'a>) -> Option<Node<'a>> {
+    let mut cursor = node.walk();
+    node.children(&mut cursor).find(|n| n.kind() == "label")
+}
+
+/// Builds a label -> definition index by walking every `mark` node in the tree.
+///
+/// A label may legally have more than one `mark` node only in a malformed
+/// program, so callers that just want "the" definition take the first entry.
+fn label_definitions<'a>(root: &Node<'a>) -> HashMap<Label, Vec<Node<'a>>> {
+    let mut definitions: HashMap<Label, Vec<Node>> = HashMap::new();
+    for mark in NodeIterator::new(*root).filter(|node| node.kind() == "mark") {
+        let mut cursor = mark.walk();
+        if let Some(label_node) = mark.children(&mut cursor).find(|n| n.kind() == "label") {
+            if let Ok(label) = Label::try_from(label_node) {
+                definitions.entry(label).or_default().push(label_node);
+            }
+        }
+    }
+    definitions
+}
+
+trait NodeRangeExt {
+    fn to_lsp_range(&self, index: &LineIndex) -> Range;
+}
+
+impl NodeRangeExt for Node<'_> {
+    fn to_lsp_range(&self, index: &LineIndex) -> Range {
+        Range {
+            start: index.to_lsp_pos(self.start_position()),
+            end: index.to_lsp_pos(self.end_position()),
+        }
+    }
+}
+
+trait LocationExt {
+    fn to_location(&self, uri: &Url, index: &LineIndex) -> Location;
+}
+
+impl LocationExt for Node<'_> {
+    fn to_location(&self, uri: &Url, index: &LineIndex) -> Location {
+        Location {
+            uri: uri.clone(),
+            range: self.to_lsp_range(index),
         }
     }
 }
 
 trait HighlightExt {
-    fn to_document_highlight(&self) -> DocumentHighlight;
+    fn to_document_highlight(&self, index: &LineIndex) -> DocumentHighlight;
 }
 
 impl HighlightExt for Node<'_> {
-    fn to_document_highlight(&self) -> DocumentHighlight {
+    fn to_document_highlight(&self, index: &LineIndex) -> DocumentHighlight {
         let kind = match self.kind() {
             n if n.starts_with("op") => DocumentHighlightKind::READ,
             "num" => DocumentHighlightKind::WRITE,
             _ => DocumentHighlightKind::TEXT,
         };
         DocumentHighlight {
-            range: Range {
-                start: self.start_position().to_lsp_pos(),
-                end: self.end_position().to_lsp_pos(),
-            },
+            range: self.to_lsp_range(index),
             kind: Some(kind),
         }
     }